@@ -11,10 +11,12 @@
 //! [cortex_m_semihosting](https://crates.io/crates/cortex-m-semihosting).
 
 #![deny(missing_docs)]
-#![no_std]
+#![cfg_attr(not(test), no_std)]
+use core::cell::UnsafeCell;
 use core::fmt;
 use core::marker::PhantomData;
 use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use embedded_hal::serial;
 use nb;
 use volatile_register::{RO, RW, WO};
@@ -72,9 +74,646 @@ pub struct PL011_Regs {
     pub uartpcellid3: RO<u32>,
 }
 
-/// Error type necessary for embedded_hal usage. No errors supported
-#[derive(Debug, Copy, Clone)]
-pub struct Error;
+/// Errors reported by the PL011 while receiving data.
+///
+/// These are decoded from the FE/PE/BE/OE status bits that accompany
+/// every word read out of `uartdr` (and are mirrored in `uartrsr`).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The receive FIFO was full and a new word arrived, overwriting
+    /// unread data (UARTRSR.OE).
+    Overrun,
+    /// A break condition was detected on the line (UARTRSR.BE).
+    Break,
+    /// The received word failed the parity check (UARTRSR.PE).
+    Parity,
+    /// The received word did not have a valid stop bit (UARTRSR.FE).
+    Framing,
+}
+
+/// Decodes the FE/PE/BE/OE error bits (bits 8-11) out of a word read
+/// from `uartdr`, in the priority order the PL011 TRM assigns them.
+fn decode_error(word: u32) -> Option<Error> {
+    if word & (1 << 11) != 0 {
+        Some(Error::Overrun)
+    } else if word & (1 << 10) != 0 {
+        Some(Error::Break)
+    } else if word & (1 << 9) != 0 {
+        Some(Error::Parity)
+    } else if word & (1 << 8) != 0 {
+        Some(Error::Framing)
+    } else {
+        None
+    }
+}
+
+/// Interrupt sources selectable through UARTIMSC/UARTRIS/UARTMIS/UARTICR.
+///
+/// Each variant corresponds to a single bit shared across those four
+/// registers, so the same mask can be used to set, query, and clear it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Interrupt {
+    /// RX FIFO level is at or above its trigger level.
+    RxFifoLevel,
+    /// TX FIFO level is at or below its trigger level.
+    TxFifoLevel,
+    /// RX FIFO has unread data but none has arrived for 32 bit periods.
+    RxTimeout,
+    /// Receive FIFO overrun (UARTRSR.OE).
+    Overrun,
+    /// Break condition detected on the line (UARTRSR.BE).
+    Break,
+    /// Parity error on a received word (UARTRSR.PE).
+    Parity,
+    /// Framing error on a received word (UARTRSR.FE).
+    Framing,
+}
+
+impl Interrupt {
+    /// The bit this interrupt occupies in UARTIMSC/UARTRIS/UARTMIS/UARTICR.
+    pub fn mask(self) -> u32 {
+        match self {
+            Interrupt::RxFifoLevel => 1 << 4,
+            Interrupt::TxFifoLevel => 1 << 5,
+            Interrupt::RxTimeout => 1 << 6,
+            Interrupt::Framing => 1 << 7,
+            Interrupt::Parity => 1 << 8,
+            Interrupt::Break => 1 << 9,
+            Interrupt::Overrun => 1 << 10,
+        }
+    }
+}
+
+/// FIFO interrupt trigger level, programmed via UARTIFLS.
+///
+/// Selects how full (RX) or how empty (TX) the 16-entry FIFO must get
+/// before its level interrupt fires.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FifoLevel {
+    /// Trigger at 1/8 full.
+    OneEighth,
+    /// Trigger at 1/4 full.
+    OneQuarter,
+    /// Trigger at 1/2 full.
+    OneHalf,
+    /// Trigger at 3/4 full.
+    ThreeQuarters,
+    /// Trigger at 7/8 full.
+    SevenEighths,
+}
+
+impl FifoLevel {
+    fn bits(self) -> u32 {
+        match self {
+            FifoLevel::OneEighth => 0b000,
+            FifoLevel::OneQuarter => 0b001,
+            FifoLevel::OneHalf => 0b010,
+            FifoLevel::ThreeQuarters => 0b011,
+            FifoLevel::SevenEighths => 0b100,
+        }
+    }
+}
+
+// The functions below are the single copy of the raw register
+// manipulation behind the `Interrupt`/`FifoLevel` APIs that `PL011`,
+// `Tx`, and `Rx` all expose; each type is just a thin wrapper passing
+// its own `regs` pointer through.
+
+/// Unmasks `interrupt` in UARTIMSC, allowing it to assert the UART's
+/// interrupt line.
+fn enable_interrupt(regs: *mut PL011_Regs, interrupt: Interrupt) {
+    unsafe {
+        let imsc = (*regs).uartimsc.read();
+        (*regs).uartimsc.write(imsc | interrupt.mask());
+    }
+}
+
+/// Masks `interrupt` in UARTIMSC, preventing it from asserting the
+/// UART's interrupt line.
+fn disable_interrupt(regs: *mut PL011_Regs, interrupt: Interrupt) {
+    unsafe {
+        let imsc = (*regs).uartimsc.read();
+        (*regs).uartimsc.write(imsc & !interrupt.mask());
+    }
+}
+
+/// Clears a latched `interrupt` by writing its bit to the write-only UARTICR.
+fn clear_interrupt(regs: *mut PL011_Regs, interrupt: Interrupt) {
+    unsafe { (*regs).uarticr.write(interrupt.mask()) };
+}
+
+/// Raw (unmasked) interrupt status, as a bitset of [`Interrupt::mask`] bits.
+fn raw_status(regs: *mut PL011_Regs) -> u32 {
+    unsafe { (*regs).uartris.read() }
+}
+
+/// Masked interrupt status, as a bitset of [`Interrupt::mask`] bits.
+fn masked_status(regs: *mut PL011_Regs) -> u32 {
+    unsafe { (*regs).uartmis.read() }
+}
+
+/// Sets how full the RX FIFO must get before [`Interrupt::RxFifoLevel`]
+/// fires, by programming UARTIFLS.RXIFLSEL.
+fn set_rx_fifo_trigger(regs: *mut PL011_Regs, level: FifoLevel) {
+    unsafe {
+        let ifls = (*regs).uartifls.read();
+        (*regs)
+            .uartifls
+            .write((ifls & !(0b111 << 3)) | (level.bits() << 3));
+    }
+}
+
+/// Sets how empty the TX FIFO must get before [`Interrupt::TxFifoLevel`]
+/// fires, by programming UARTIFLS.TXIFLSEL.
+fn set_tx_fifo_trigger(regs: *mut PL011_Regs, level: FifoLevel) {
+    unsafe {
+        let ifls = (*regs).uartifls.read();
+        (*regs).uartifls.write((ifls & !0b111) | level.bits());
+    }
+}
+
+/// A lock-free single-producer/single-consumer ring buffer over a
+/// caller-supplied `'static` byte slice.
+///
+/// Meant to be shared between an interrupt handler, which is the sole
+/// producer, and a task, which is the sole consumer: the producer only
+/// ever advances `end` and the consumer only ever advances `start`, so
+/// the two sides never need a lock between them.
+struct RingBuffer {
+    buf: *mut u8,
+    len: usize,
+    start: AtomicUsize,
+    end: AtomicUsize,
+    overflowed: AtomicBool,
+    errors: AtomicUsize,
+}
+
+// Safety: `buf` is only ever written at `end` by the producer and read
+// at `start` by the consumer, and those indices never overlap.
+unsafe impl Send for RingBuffer {}
+unsafe impl Sync for RingBuffer {}
+
+impl RingBuffer {
+    fn new(buf: &'static mut [u8]) -> Self {
+        Self {
+            len: buf.len(),
+            buf: buf.as_mut_ptr(),
+            start: AtomicUsize::new(0),
+            end: AtomicUsize::new(0),
+            overflowed: AtomicBool::new(false),
+            errors: AtomicUsize::new(0),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.start.load(Ordering::Acquire) == self.end.load(Ordering::Acquire)
+    }
+
+    fn is_full(&self) -> bool {
+        let end = self.end.load(Ordering::Acquire);
+        let start = self.start.load(Ordering::Acquire);
+        (end + 1) % self.len == start
+    }
+
+    /// Pushes a byte from the producer (interrupt) side. Returns `false`
+    /// and flags an overflow, discarding `byte`, if the buffer is full.
+    fn push(&self, byte: u8) -> bool {
+        if self.is_full() {
+            self.overflowed.store(true, Ordering::Relaxed);
+            return false;
+        }
+        let end = self.end.load(Ordering::Acquire);
+        unsafe { self.buf.add(end).write(byte) };
+        self.end.store((end + 1) % self.len, Ordering::Release);
+        true
+    }
+
+    /// Pops a byte from the consumer (task) side, or `None` if empty.
+    fn pop(&self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let start = self.start.load(Ordering::Acquire);
+        let byte = unsafe { self.buf.add(start).read() };
+        self.start.store((start + 1) % self.len, Ordering::Release);
+        Some(byte)
+    }
+
+    /// Returns whether a push has been dropped since the last call, clearing the flag.
+    fn take_overflowed(&self) -> bool {
+        self.overflowed.swap(false, Ordering::Relaxed)
+    }
+
+    /// Records a byte discarded because the PL011 flagged it with an
+    /// overrun, break, parity, or framing condition.
+    fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of errored bytes discarded since the last call,
+    /// clearing the counter.
+    fn take_errors(&self) -> usize {
+        self.errors.swap(0, Ordering::Relaxed)
+    }
+}
+
+/// The transmit half of a [`PL011`], produced by [`PL011::split`].
+///
+/// Only ever touches `uartdr` (as a write) and the TXFF bit of `uartfr`
+/// as data-path registers; the interrupt/FIFO-trigger registers it also
+/// exposes are shared with [`Rx`], which owns the RX side of the same bits.
+pub struct Tx {
+    regs: *mut PL011_Regs,
+}
+
+// Safety: `Tx` only ever touches `uartdr` (as a write) and the TXFF bit
+// of `uartfr` on the data path, disjoint from the registers `Rx` uses,
+// so it's safe to move to another execution context (e.g. a separate
+// TX task).
+unsafe impl Send for Tx {}
+
+impl Tx {
+    /// writes a single byte out the uart
+    ///
+    /// spins until space is available in the fifo
+    pub fn write_byte(&self, data: u8) {
+        while !self.is_writeable() {}
+        unsafe { (*self.regs).uartdr.write(data as u32) };
+    }
+
+    /// Is the transmit-buffer-full flag clear?
+    pub fn is_writeable(&self) -> bool {
+        let uartfr = unsafe { (*self.regs).uartfr.read() };
+        uartfr & 0x20 == 0
+    }
+
+    /// Unmasks `interrupt`, allowing it to assert the UART's interrupt line.
+    pub fn enable_interrupt(&self, interrupt: Interrupt) {
+        enable_interrupt(self.regs, interrupt)
+    }
+
+    /// Masks `interrupt`, preventing it from asserting the UART's interrupt line.
+    pub fn disable_interrupt(&self, interrupt: Interrupt) {
+        disable_interrupt(self.regs, interrupt)
+    }
+
+    /// Clears a latched `interrupt` by writing its bit to the write-only UARTICR.
+    pub fn clear_interrupt(&self, interrupt: Interrupt) {
+        clear_interrupt(self.regs, interrupt)
+    }
+
+    /// Raw (unmasked) interrupt status, as a bitset of [`Interrupt::mask`] bits.
+    pub fn raw_status(&self) -> u32 {
+        raw_status(self.regs)
+    }
+
+    /// Masked interrupt status, as a bitset of [`Interrupt::mask`] bits.
+    pub fn masked_status(&self) -> u32 {
+        masked_status(self.regs)
+    }
+
+    /// Sets how empty the TX FIFO must get before [`Interrupt::TxFifoLevel`]
+    /// fires, by programming UARTIFLS.TXIFLSEL.
+    pub fn set_tx_fifo_trigger(&self, level: FifoLevel) {
+        set_tx_fifo_trigger(self.regs, level)
+    }
+}
+
+impl serial::Write<u8> for Tx {
+    type Error = Error;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        self.flush()?;
+        unsafe { (*self.regs).uartdr.write(word as u32) };
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        if self.is_writeable() {
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+impl fmt::Write for Tx {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        use embedded_hal::serial::Write;
+        for b in s.as_bytes().iter() {
+            if nb::block!(self.write(*b)).is_err() {
+                return Err(fmt::Error);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The receive half of a [`PL011`], produced by [`PL011::split`].
+///
+/// Only ever touches `uartdr` (as a read), `uartrsr`, and the RXFE bit of
+/// `uartfr` as data-path registers; the interrupt/FIFO-trigger registers
+/// it also exposes are shared with [`Tx`], which owns the TX side of the
+/// same bits.
+pub struct Rx {
+    regs: *mut PL011_Regs,
+    rx_buffer: UnsafeCell<Option<RingBuffer>>,
+}
+
+// Safety: `Rx` only ever touches `uartdr` (as a read), `uartrsr`, and the
+// RXFE bit of `uartfr` on the data path, disjoint from the registers
+// `Tx` uses, so it's safe to move to another execution context (e.g. an
+// RX interrupt handler); `rx_buffer`'s `RingBuffer` is itself `Send`.
+unsafe impl Send for Rx {}
+
+impl Rx {
+    /// Is the receive-buffer-empty flag clear?
+    pub fn has_incoming_data(&self) -> bool {
+        let uartfr = unsafe { (*self.regs).uartfr.read() };
+        uartfr & 0x10 == 0
+    }
+
+    /// reads a single byte out the uart
+    ///
+    /// spins until a byte is available in the fifo, then returns it
+    /// unless the PL011 flagged an overrun, break, parity, or framing
+    /// condition on it, in which case that condition is cleared and
+    /// reported instead
+    pub fn read_byte(&self) -> Result<u8, Error> {
+        while !self.has_incoming_data() {}
+        let word = unsafe { (*self.regs).uartdr.read() };
+        if let Some(e) = decode_error(word) {
+            // writing any value to uartrsr clears the error condition
+            unsafe { (*self.regs).uartrsr.write(0) };
+            return Err(e);
+        }
+        Ok((word & 0xff) as u8)
+    }
+
+    /// Unmasks `interrupt`, allowing it to assert the UART's interrupt line.
+    pub fn enable_interrupt(&self, interrupt: Interrupt) {
+        enable_interrupt(self.regs, interrupt)
+    }
+
+    /// Masks `interrupt`, preventing it from asserting the UART's interrupt line.
+    pub fn disable_interrupt(&self, interrupt: Interrupt) {
+        disable_interrupt(self.regs, interrupt)
+    }
+
+    /// Clears a latched `interrupt` by writing its bit to the write-only UARTICR.
+    pub fn clear_interrupt(&self, interrupt: Interrupt) {
+        clear_interrupt(self.regs, interrupt)
+    }
+
+    /// Raw (unmasked) interrupt status, as a bitset of [`Interrupt::mask`] bits.
+    pub fn raw_status(&self) -> u32 {
+        raw_status(self.regs)
+    }
+
+    /// Masked interrupt status, as a bitset of [`Interrupt::mask`] bits.
+    pub fn masked_status(&self) -> u32 {
+        masked_status(self.regs)
+    }
+
+    /// Sets how full the RX FIFO must get before [`Interrupt::RxFifoLevel`]
+    /// fires, by programming UARTIFLS.RXIFLSEL.
+    pub fn set_rx_fifo_trigger(&self, level: FifoLevel) {
+        set_rx_fifo_trigger(self.regs, level)
+    }
+
+    /// Attaches a ring buffer backed by `buf` so that [`Rx::irq_handler`]
+    /// can drain the RX FIFO in interrupt context and [`Rx::read_buffered`]
+    /// can consume bytes later from a task.
+    pub fn attach_rx_buffer(&self, buf: &'static mut [u8]) {
+        unsafe { *self.rx_buffer.get() = Some(RingBuffer::new(buf)) };
+    }
+
+    /// Interrupt handler: on an RX-FIFO-level or RX-timeout interrupt,
+    /// drains the hardware FIFO into the attached ring buffer.
+    ///
+    /// Words flagged with an overrun, break, parity, or framing condition
+    /// are not pushed into the buffer; the condition is cleared and the
+    /// byte is counted instead, queryable with [`Rx::rx_errors`]. Bytes
+    /// are also discarded (and an overflow is flagged, queryable with
+    /// [`Rx::rx_overflowed`]) if the ring buffer fills up. If no buffer
+    /// has been attached via [`Rx::attach_rx_buffer`], the RX interrupts
+    /// are still cleared but the hardware FIFO is left undrained.
+    pub fn irq_handler(&self) {
+        let pending = masked_status(self.regs);
+        if pending & (Interrupt::RxFifoLevel.mask() | Interrupt::RxTimeout.mask()) == 0 {
+            return;
+        }
+        clear_interrupt(self.regs, Interrupt::RxFifoLevel);
+        clear_interrupt(self.regs, Interrupt::RxTimeout);
+
+        let ring = match unsafe { (*self.rx_buffer.get()).as_ref() } {
+            Some(ring) => ring,
+            None => return,
+        };
+        while self.has_incoming_data() {
+            let word = unsafe { (*self.regs).uartdr.read() };
+            if decode_error(word).is_some() {
+                // writing any value to uartrsr clears the error condition
+                unsafe { (*self.regs).uartrsr.write(0) };
+                ring.record_error();
+                continue;
+            }
+            ring.push((word & 0xff) as u8);
+        }
+    }
+
+    /// Non-blocking read out of the ring buffer attached via
+    /// [`Rx::attach_rx_buffer`], returning the number of bytes copied
+    /// into `buf`. Returns `0` if no buffer is attached or none is ready.
+    pub fn read_buffered(&self, buf: &mut [u8]) -> usize {
+        let ring = match unsafe { (*self.rx_buffer.get()).as_ref() } {
+            Some(ring) => ring,
+            None => return 0,
+        };
+        let mut read = 0;
+        while read < buf.len() {
+            match ring.pop() {
+                Some(byte) => {
+                    buf[read] = byte;
+                    read += 1;
+                }
+                None => break,
+            }
+        }
+        read
+    }
+
+    /// Whether a byte has been dropped because the ring buffer attached
+    /// via [`Rx::attach_rx_buffer`] filled up, clearing the flag.
+    pub fn rx_overflowed(&self) -> bool {
+        match unsafe { (*self.rx_buffer.get()).as_ref() } {
+            Some(ring) => ring.take_overflowed(),
+            None => false,
+        }
+    }
+
+    /// The number of bytes [`Rx::irq_handler`] has discarded because the
+    /// PL011 flagged them with an overrun, break, parity, or framing
+    /// condition, clearing the counter.
+    pub fn rx_errors(&self) -> usize {
+        match unsafe { (*self.rx_buffer.get()).as_ref() } {
+            Some(ring) => ring.take_errors(),
+            None => 0,
+        }
+    }
+}
+
+impl serial::Read<u8> for Rx {
+    type Error = Error;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        if !self.has_incoming_data() {
+            return Err(nb::Error::WouldBlock);
+        }
+        let word = unsafe { (*self.regs).uartdr.read() };
+        if let Some(e) = decode_error(word) {
+            unsafe { (*self.regs).uartrsr.write(0) };
+            return Err(nb::Error::Other(e));
+        }
+        Ok((word & 0xff) as u8)
+    }
+}
+
+/// Number of data bits per word, programmed via UARTLCR_H.WLEN.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WordLength {
+    /// 5 data bits.
+    Five,
+    /// 6 data bits.
+    Six,
+    /// 7 data bits.
+    Seven,
+    /// 8 data bits.
+    Eight,
+}
+
+impl WordLength {
+    fn bits(self) -> u32 {
+        let wlen = match self {
+            WordLength::Five => 0b00,
+            WordLength::Six => 0b01,
+            WordLength::Seven => 0b10,
+            WordLength::Eight => 0b11,
+        };
+        wlen << 5
+    }
+}
+
+/// Parity mode, programmed via UARTLCR_H.PEN/EPS/SPS.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Parity {
+    /// No parity bit.
+    None,
+    /// Odd parity.
+    Odd,
+    /// Even parity.
+    Even,
+    /// Parity bit fixed to 1 ("stick parity").
+    StickOne,
+    /// Parity bit fixed to 0 ("stick parity").
+    StickZero,
+}
+
+impl Parity {
+    fn bits(self) -> u32 {
+        const PEN: u32 = 1 << 1;
+        const EPS: u32 = 1 << 2;
+        const SPS: u32 = 1 << 7;
+        match self {
+            Parity::None => 0,
+            Parity::Odd => PEN,
+            Parity::Even => PEN | EPS,
+            Parity::StickOne => PEN | SPS,
+            Parity::StickZero => PEN | EPS | SPS,
+        }
+    }
+}
+
+/// Number of stop bits, programmed via UARTLCR_H.STP2.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StopBits {
+    /// One stop bit.
+    One,
+    /// Two stop bits.
+    Two,
+}
+
+impl StopBits {
+    fn bits(self) -> u32 {
+        match self {
+            StopBits::One => 0,
+            StopBits::Two => 1 << 3,
+        }
+    }
+}
+
+/// Line-control configuration applied through UARTLCR_H by
+/// [`PL011::new_with_config`].
+///
+/// Defaults to 8 data bits, no parity, one stop bit, FIFOs enabled,
+/// which matches how the stock QEMU virt UART already behaves.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Config {
+    word_length: WordLength,
+    parity: Parity,
+    stop_bits: StopBits,
+    fifos_enabled: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            word_length: WordLength::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            fifos_enabled: true,
+        }
+    }
+}
+
+impl Config {
+    /// Starts from the default 8N1, FIFOs-enabled configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the number of data bits per word.
+    pub fn word_length(mut self, word_length: WordLength) -> Self {
+        self.word_length = word_length;
+        self
+    }
+
+    /// Sets the parity mode.
+    pub fn parity(mut self, parity: Parity) -> Self {
+        self.parity = parity;
+        self
+    }
+
+    /// Sets the number of stop bits.
+    pub fn stop_bits(mut self, stop_bits: StopBits) -> Self {
+        self.stop_bits = stop_bits;
+        self
+    }
+
+    /// Enables or disables the TX/RX FIFOs.
+    pub fn fifos_enabled(mut self, fifos_enabled: bool) -> Self {
+        self.fifos_enabled = fifos_enabled;
+        self
+    }
+
+    fn uartlcr_h(self) -> u32 {
+        let mut bits = self.word_length.bits() | self.parity.bits() | self.stop_bits.bits();
+        if self.fifos_enabled {
+            bits |= 1 << 4; // FEN
+        }
+        bits
+    }
+}
 
 /// Struct representing the actual driver.
 ///
@@ -85,6 +724,9 @@ pub struct Error;
 ///
 /// Implements embedded_hal::serial as well as core::fmt::Write
 ///
+/// Internally this is just a [`Tx`] and an [`Rx`] held together; see
+/// [`PL011::split`] to own them from separate contexts.
+///
 /// # Examples
 ///
 /// ```
@@ -93,26 +735,25 @@ pub struct Error;
 /// let mut uart = pl011_qemu::PL011::new(pl011_qemu::UART1::take().unwrap());
 /// ```
 pub struct PL011 {
-    regs: &'static mut PL011_Regs,
+    tx: Tx,
+    rx: Rx,
 }
 
 /// RX methods
 impl PL011 {
     /// Is the receive-buffer-empty flag clear?
     pub fn has_incoming_data(&self) -> bool {
-        let uartfr = unsafe { (*self.regs).uartfr.read() };
-        uartfr & 0x10 == 0
+        self.rx.has_incoming_data()
     }
 
     /// reads a single byte out the uart
     ///
-    /// spins until a byte is available in the fifo
-    pub fn read_byte(&self) -> u8 {
-        // loop while RXFE is set
-        while !self.has_incoming_data() {}
-        // read the data register. Atomic read is side effect free
-        let data = unsafe { (*self.regs).uartdr.read() & 0xff };
-        data as u8
+    /// spins until a byte is available in the fifo, then returns it
+    /// unless the PL011 flagged an overrun, break, parity, or framing
+    /// condition on it, in which case that condition is cleared and
+    /// reported instead
+    pub fn read_byte(&self) -> Result<u8, Error> {
+        self.rx.read_byte()
     }
 }
 
@@ -122,14 +763,91 @@ impl PL011 {
     ///
     /// spins until space is available in the fifo
     pub fn write_byte(&self, data: u8) {
-        while !self.is_writeable() {}
-        unsafe { (*self.regs).uartdr.write(data as u32) };
+        self.tx.write_byte(data)
     }
 
     /// Is the transmit-buffer-full flag clear?
     pub fn is_writeable(&self) -> bool {
-        let uartfr = unsafe { (*self.regs).uartfr.read() };
-        uartfr & 0x20 == 0
+        self.tx.is_writeable()
+    }
+}
+
+/// Interrupt configuration and status
+impl PL011 {
+    /// Unmasks `interrupt`, allowing it to assert the UART's interrupt line.
+    pub fn enable_interrupt(&self, interrupt: Interrupt) {
+        self.tx.enable_interrupt(interrupt)
+    }
+
+    /// Masks `interrupt`, preventing it from asserting the UART's interrupt line.
+    pub fn disable_interrupt(&self, interrupt: Interrupt) {
+        self.tx.disable_interrupt(interrupt)
+    }
+
+    /// Clears a latched `interrupt` by writing its bit to the write-only UARTICR.
+    pub fn clear_interrupt(&self, interrupt: Interrupt) {
+        self.tx.clear_interrupt(interrupt)
+    }
+
+    /// Raw (unmasked) interrupt status, as a bitset of [`Interrupt::mask`] bits.
+    pub fn raw_status(&self) -> u32 {
+        self.tx.raw_status()
+    }
+
+    /// Masked interrupt status, as a bitset of [`Interrupt::mask`] bits.
+    ///
+    /// Only interrupts enabled via [`PL011::enable_interrupt`] can appear here.
+    pub fn masked_status(&self) -> u32 {
+        self.tx.masked_status()
+    }
+
+    /// Sets how full the RX FIFO must get before [`Interrupt::RxFifoLevel`]
+    /// fires, by programming UARTIFLS.RXIFLSEL.
+    pub fn set_rx_fifo_trigger(&self, level: FifoLevel) {
+        self.rx.set_rx_fifo_trigger(level)
+    }
+
+    /// Sets how empty the TX FIFO must get before [`Interrupt::TxFifoLevel`]
+    /// fires, by programming UARTIFLS.TXIFLSEL.
+    pub fn set_tx_fifo_trigger(&self, level: FifoLevel) {
+        self.tx.set_tx_fifo_trigger(level)
+    }
+}
+
+/// Buffered (interrupt-driven) RX
+impl PL011 {
+    /// Attaches a ring buffer backed by `buf` so that [`PL011::irq_handler`]
+    /// can drain the RX FIFO in interrupt context and [`PL011::read_buffered`]
+    /// can consume bytes later from a task.
+    pub fn attach_rx_buffer(&self, buf: &'static mut [u8]) {
+        self.rx.attach_rx_buffer(buf)
+    }
+
+    /// Interrupt handler: on an RX-FIFO-level or RX-timeout interrupt,
+    /// drains the hardware FIFO into the attached ring buffer. See
+    /// [`Rx::irq_handler`] for the full behavior.
+    pub fn irq_handler(&self) {
+        self.rx.irq_handler()
+    }
+
+    /// Non-blocking read out of the ring buffer attached via
+    /// [`PL011::attach_rx_buffer`], returning the number of bytes copied
+    /// into `buf`. Returns `0` if no buffer is attached or none is ready.
+    pub fn read_buffered(&self, buf: &mut [u8]) -> usize {
+        self.rx.read_buffered(buf)
+    }
+
+    /// Whether a byte has been dropped because the ring buffer attached
+    /// via [`PL011::attach_rx_buffer`] filled up, clearing the flag.
+    pub fn rx_overflowed(&self) -> bool {
+        self.rx.rx_overflowed()
+    }
+
+    /// The number of bytes [`PL011::irq_handler`] has discarded because
+    /// the PL011 flagged them with an overrun, break, parity, or framing
+    /// condition, clearing the counter.
+    pub fn rx_errors(&self) -> usize {
+        self.rx.rx_errors()
     }
 }
 
@@ -137,12 +855,7 @@ impl serial::Read<u8> for PL011 {
     type Error = Error;
 
     fn read(&mut self) -> nb::Result<u8, Self::Error> {
-        // if RXFE is set (rx fifo is empty)
-        if self.has_incoming_data() {
-            Ok(unsafe { (*self.regs).uartdr.read() & 0xff } as u8)
-        } else {
-            Err(nb::Error::WouldBlock)
-        }
+        self.rx.read()
     }
 }
 
@@ -150,28 +863,70 @@ impl serial::Write<u8> for PL011 {
     type Error = Error;
 
     fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
-        self.flush()?;
-        unsafe { (*self.regs).uartdr.write(word as u32) };
-        Ok(())
+        self.tx.write(word)
     }
 
     fn flush(&mut self) -> nb::Result<(), Self::Error> {
-        if self.is_writeable() {
-            Ok(())
-        } else {
-            Err(nb::Error::WouldBlock)
-        }
+        self.tx.flush()
     }
 }
 
 impl fmt::Write for PL011 {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        use embedded_hal::serial::Write;
-        for b in s.as_bytes().iter() {
-            if nb::block!(self.write(*b)).is_err() {
-                return Err(fmt::Error);
+        self.tx.write_str(s)
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl embedded_io::Error for Error {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        embedded_io::ErrorKind::Other
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl embedded_io::ErrorType for PL011 {
+    type Error = Error;
+}
+
+#[cfg(feature = "embedded-io")]
+impl embedded_io::Read for PL011 {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // spin until at least one byte is available, then drain whatever
+        // else is already sitting in the fifo
+        while !self.rx.has_incoming_data() {}
+        let mut read = 0;
+        while read < buf.len() && self.rx.has_incoming_data() {
+            match self.rx.read_byte() {
+                Ok(byte) => {
+                    buf[read] = byte;
+                    read += 1;
+                }
+                // report bytes already read this call instead of the error;
+                // the errored word was consumed and its condition cleared by
+                // `read_byte`, so the error is not reported on a later call
+                Err(_) if read > 0 => return Ok(read),
+                Err(e) => return Err(e),
             }
         }
+        Ok(read)
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl embedded_io::Write for PL011 {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        for &b in buf {
+            self.tx.write_byte(b);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        while !self.tx.is_writeable() {}
         Ok(())
     }
 }
@@ -180,7 +935,177 @@ impl fmt::Write for PL011 {
 impl PL011 {
     /// Initialize a UART driver. Needs a UART struct to be passed in
     pub fn new(regs: *mut PL011_Regs) -> Self {
-        let regs = unsafe { regs.as_mut() }.unwrap();
-        Self { regs }
+        Self {
+            tx: Tx { regs },
+            rx: Rx {
+                regs,
+                rx_buffer: UnsafeCell::new(None),
+            },
+        }
+    }
+
+    /// Initializes a UART driver and applies `config` through UARTLCR_H
+    /// and UARTCR.
+    ///
+    /// Follows the sequence the PL011 TRM requires: UARTEN is cleared
+    /// before UARTLCR_H is reprogrammed, and UARTEN/TXE/RXE are set
+    /// afterwards to re-enable the UART.
+    pub fn new_with_config(regs: *mut PL011_Regs, config: Config) -> Self {
+        unsafe { (*regs).uartcr.write(0) };
+        unsafe { (*regs).uartlcr_h.write(config.uartlcr_h()) };
+        // UARTEN | TXE | RXE
+        unsafe { (*regs).uartcr.write((1 << 0) | (1 << 8) | (1 << 9)) };
+        Self::new(regs)
+    }
+
+    /// Splits the driver into independent transmit and receive halves, so
+    /// e.g. an RX interrupt handler and the TX path can be owned by
+    /// separate tasks. Any ring buffer attached via
+    /// [`PL011::attach_rx_buffer`] carries over to `Rx`. Recombine with
+    /// [`PL011::reunite`].
+    pub fn split(self) -> (Tx, Rx) {
+        (self.tx, self.rx)
+    }
+
+    /// Recombines the halves produced by [`PL011::split`] back into a
+    /// single driver, preserving any ring buffer attached to `rx`.
+    pub fn reunite(tx: Tx, rx: Rx) -> PL011 {
+        debug_assert_eq!(
+            tx.regs, rx.regs,
+            "Tx and Rx halves came from different PL011 instances"
+        );
+        PL011 { tx, rx }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(len: usize) -> RingBuffer {
+        let buf: &'static mut [u8] = Box::leak(vec![0u8; len].into_boxed_slice());
+        RingBuffer::new(buf)
+    }
+
+    #[test]
+    fn starts_empty() {
+        let ring = ring(4);
+        assert!(ring.is_empty());
+        assert!(!ring.is_full());
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn push_pop_round_trip() {
+        let ring = ring(4);
+        assert!(ring.push(1));
+        assert!(ring.push(2));
+        assert!(!ring.is_empty());
+        assert_eq!(ring.pop(), Some(1));
+        assert_eq!(ring.pop(), Some(2));
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn fills_one_short_of_len() {
+        // a len-4 buffer only ever holds 3 bytes: `is_full` reserves one
+        // slot to tell "full" apart from "empty"
+        let ring = ring(4);
+        assert!(ring.push(1));
+        assert!(ring.push(2));
+        assert!(ring.push(3));
+        assert!(ring.is_full());
+        assert!(!ring.push(4));
+        assert!(ring.take_overflowed());
+    }
+
+    #[test]
+    fn indices_wrap_around() {
+        let ring = ring(4);
+        assert!(ring.push(1));
+        assert!(ring.push(2));
+        assert_eq!(ring.pop(), Some(1));
+        assert_eq!(ring.pop(), Some(2));
+        // start/end now sit at 2, so the next two pushes and pops carry
+        // them past the end of the backing slice and back to 0
+        assert!(ring.push(3));
+        assert!(ring.push(4));
+        assert_eq!(ring.pop(), Some(3));
+        assert_eq!(ring.pop(), Some(4));
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn overflow_flag_clears_once_taken() {
+        let ring = ring(2);
+        assert!(ring.push(1));
+        assert!(!ring.push(2)); // len-2 buffer holds only 1 byte
+        assert!(ring.take_overflowed());
+        assert!(!ring.take_overflowed());
+    }
+
+    #[test]
+    fn errors_counter_clears_once_taken() {
+        let ring = ring(2);
+        ring.record_error();
+        ring.record_error();
+        assert_eq!(ring.take_errors(), 2);
+        assert_eq!(ring.take_errors(), 0);
+    }
+
+    #[test]
+    fn decode_error_picks_highest_priority_bit() {
+        assert_eq!(decode_error(0), None);
+        assert_eq!(decode_error(1 << 8), Some(Error::Framing));
+        assert_eq!(decode_error(1 << 9), Some(Error::Parity));
+        assert_eq!(decode_error(1 << 10), Some(Error::Break));
+        assert_eq!(decode_error(1 << 11), Some(Error::Overrun));
+        // overrun takes priority when multiple error bits are set
+        assert_eq!(decode_error((1 << 8) | (1 << 11)), Some(Error::Overrun));
+    }
+
+    #[test]
+    fn interrupt_masks_are_distinct_single_bits() {
+        let masks = [
+            Interrupt::RxFifoLevel.mask(),
+            Interrupt::TxFifoLevel.mask(),
+            Interrupt::RxTimeout.mask(),
+            Interrupt::Overrun.mask(),
+            Interrupt::Break.mask(),
+            Interrupt::Parity.mask(),
+            Interrupt::Framing.mask(),
+        ];
+        for (i, a) in masks.iter().enumerate() {
+            assert_eq!(a.count_ones(), 1);
+            for (j, b) in masks.iter().enumerate() {
+                if i != j {
+                    assert_eq!(a & b, 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fifo_level_bits_are_ordered() {
+        assert_eq!(FifoLevel::OneEighth.bits(), 0b000);
+        assert_eq!(FifoLevel::OneQuarter.bits(), 0b001);
+        assert_eq!(FifoLevel::OneHalf.bits(), 0b010);
+        assert_eq!(FifoLevel::ThreeQuarters.bits(), 0b011);
+        assert_eq!(FifoLevel::SevenEighths.bits(), 0b100);
+    }
+
+    #[test]
+    fn config_programs_expected_uartlcr_h_bits() {
+        let bits = Config::new()
+            .word_length(WordLength::Seven)
+            .parity(Parity::Even)
+            .stop_bits(StopBits::Two)
+            .fifos_enabled(false)
+            .uartlcr_h();
+        assert_eq!(bits & (0b11 << 5), 0b10 << 5); // WLEN = 7 bits
+        assert_eq!(bits & (1 << 3), 1 << 3); // STP2
+        assert_eq!(bits & (1 << 1), 1 << 1); // PEN
+        assert_eq!(bits & (1 << 2), 1 << 2); // EPS
+        assert_eq!(bits & (1 << 4), 0); // FEN off
     }
 }